@@ -1,82 +1,560 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::io;
 use std::io::Cursor;
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 use assembler::{Command, CommandType};
 use tokenizer::*;
 
+/// A fault raised while executing a single instruction. Every memory and
+/// register access is bounds-checked before it is performed, so a malformed
+/// or malicious program traps instead of crashing the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeError {
+    InvalidMemoryAccess { address: usize },
+    MemoryAlignment { address: usize },
+    UnmappedPage { address: usize },
+    DivideByZero,
+    ArithmeticOverflow,
+    InvalidRegister,
+    InvalidOpcode
+}
+
+/// The outcome of a VM run that didn't trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltStatus {
+    // The program reached an `End` instruction
+    End,
+    // Execution stopped because PC landed on a breakpoint
+    Breakpoint
+}
+
+/// Lifecycle of the VM: `Init` until the first `step`, `Running` once an
+/// instruction has started executing, and `Halted` after an `End`
+/// instruction. A step that traps leaves the state at `Running`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Init,
+    Running,
+    Halted
+}
+
+/// The result of executing exactly one instruction via `VM::step`. Breakpoints
+/// are a `run`-only concept: `step` always executes, so this never carries a
+/// `Breakpoint` case. A caller driving the VM directly via `step` that wants
+/// breakpoint semantics should check `VM::is_breakpoint` on the current PC
+/// itself before stepping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Running,
+    Halted(HaltStatus)
+}
+
+// Bits of the flags register, set after every arithmetic/logic instruction
+const FLAG_ZERO: u8 = 1 << 0;
+const FLAG_NEGATIVE: u8 = 1 << 1;
+const FLAG_CARRY: u8 = 1 << 2;
+const FLAG_OVERFLOW: u8 = 1 << 3;
+
+/// Selects which numeric domain `Add`/`Subtract`/`Multiply`/`Divide`/`Compare`
+/// operate on. Registers stay raw 32-bit cells; this just changes how their
+/// bits are interpreted for a given instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MathOpSubType {
+    Signed,
+    Unsigned,
+    FloatingPoint
+}
+
+impl MathOpSubType {
+    // The opcode occupies the low 16 bits of the instruction's first
+    // bytecode word; the sub-type selector lives in the 2 bits above it
+    fn from_bytecode(word: i32) -> MathOpSubType {
+        match (word >> 16) & 0b11 {
+            1 => MathOpSubType::Unsigned,
+            2 => MathOpSubType::FloatingPoint,
+            _ => MathOpSubType::Signed
+        }
+    }
+}
+
+// The address space is fixed, but it's no longer eagerly allocated: pages
+// are faulted in lazily as the program touches them
+pub const ADDRESS_SPACE: usize = 10_000_000; // 10MB
+const PAGE_SIZE: usize = 4096;
+
+struct Page {
+    data: [u8; PAGE_SIZE]
+}
+
+impl Page {
+    fn new() -> Page {
+        Page { data: [0; PAGE_SIZE] }
+    }
+}
+
+/// A sparse, page-backed view of the address space. Pages are faulted in on
+/// first access rather than eagerly allocated, and whether an unmapped page
+/// auto-maps or traps is controlled by `auto_map`.
+struct Memory {
+    pages: HashMap<usize, Page>,
+    auto_map: bool
+}
+
+impl Memory {
+    fn new(auto_map: bool) -> Memory {
+        Memory {
+            pages: HashMap::new(),
+            auto_map: auto_map
+        }
+    }
+
+    fn locate(address: usize) -> (usize, usize) {
+        (address / PAGE_SIZE, address % PAGE_SIZE)
+    }
+
+    // Always maps the touched pages regardless of `auto_map`, used to seed
+    // the initial program image at startup
+    fn load(&mut self, address: usize, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            let (page, index) = Memory::locate(address + offset);
+            self.pages.entry(page).or_insert_with(Page::new).data[index] = *byte;
+        }
+    }
+
+    fn read_byte(&mut self, address: usize) -> Result<u8, RuntimeError> {
+        let (page, index) = Memory::locate(address);
+        if !self.pages.contains_key(&page) {
+            if self.auto_map {
+                self.pages.insert(page, Page::new());
+            } else {
+                return Err(RuntimeError::UnmappedPage { address: address });
+            }
+        }
+        Ok(self.pages[&page].data[index])
+    }
+
+    fn write_byte(&mut self, address: usize, value: u8) -> Result<(), RuntimeError> {
+        let (page, index) = Memory::locate(address);
+        if !self.pages.contains_key(&page) {
+            if self.auto_map {
+                self.pages.insert(page, Page::new());
+            } else {
+                return Err(RuntimeError::UnmappedPage { address: address });
+            }
+        }
+        self.pages.get_mut(&page).unwrap().data[index] = value;
+        Ok(())
+    }
+
+    // Reads a byte without faulting in a page, for inspecting state (e.g.
+    // a debugger dump) without side effects; unmapped pages read as zero
+    fn peek_byte(&self, address: usize) -> u8 {
+        let (page, index) = Memory::locate(address);
+        self.pages.get(&page).map_or(0, |page| page.data[index])
+    }
+}
+
+// Memory-mapped I/O region, carved out of the top of `memory`: a VRAM page
+// the host renders as a framebuffer, a keyboard cell populated by the host
+// device, and a status cell a program can poll to see if a key is waiting
+pub const MMIO_BASE: usize = 9_000_000;
+pub const VRAM_WIDTH: usize = 80;
+pub const VRAM_HEIGHT: usize = 25;
+pub const VRAM_SIZE: usize = VRAM_WIDTH * VRAM_HEIGHT;
+pub const VRAM_ADDRESS: usize = MMIO_BASE;
+pub const KEYBOARD_ADDRESS: usize = VRAM_ADDRESS + VRAM_SIZE;
+pub const DEVICE_STATUS_ADDRESS: usize = KEYBOARD_ADDRESS + 1;
+const MMIO_END: usize = DEVICE_STATUS_ADDRESS + 1;
+
+/// A pluggable I/O backend for the memory-mapped region. `StoreByte`/
+/// `StoreWord`/`LoadByte`/`LoadWord` route accesses in that region here
+/// instead of touching `memory` directly, so a host can swap in a real
+/// terminal or a mock for headless testing.
+pub trait Device {
+    fn on_write(&mut self, address: usize, value: u8);
+    fn on_read(&mut self, address: usize) -> u8;
+    fn poll(&mut self);
+
+    // Reads a little-endian word spanning `address` and `address + 1` as a
+    // single logical access. The default just stitches together two
+    // `on_read` calls; override this when, like the keyboard/status pair, a
+    // narrower read has a side effect that a wider read shouldn't observe
+    // only half of.
+    fn on_read_word(&mut self, address: usize) -> u16 {
+        let low = self.on_read(address);
+        let high = self.on_read(address + 1);
+        u16::from(low) | (u16::from(high) << 8)
+    }
+}
+
+/// A `Device` that paints VRAM writes to the host terminal and fills the
+/// keyboard cell from stdin when polled.
+pub struct TerminalDevice {
+    framebuffer: Vec<u8>,
+    keyboard: u8,
+    status: u8
+}
+
+impl TerminalDevice {
+    pub fn new() -> TerminalDevice {
+        TerminalDevice {
+            framebuffer: vec![0; VRAM_SIZE],
+            keyboard: 0,
+            status: 0
+        }
+    }
+}
+
+impl Device for TerminalDevice {
+    fn on_write(&mut self, address: usize, value: u8) {
+        if address < KEYBOARD_ADDRESS {
+            self.framebuffer[address - VRAM_ADDRESS] = value;
+            print!("{}", value as char);
+        }
+    }
+
+    fn on_read(&mut self, address: usize) -> u8 {
+        if address < KEYBOARD_ADDRESS {
+            self.framebuffer[address - VRAM_ADDRESS]
+        } else if address == KEYBOARD_ADDRESS {
+            self.status = 0;
+            self.keyboard
+        } else {
+            self.status
+        }
+    }
+
+    // Reading the keyboard cell clears `status` as a side effect, so a
+    // two-byte read starting there must capture `status` for the high byte
+    // before `on_read`'s low-byte read zeroes it out from under it
+    fn on_read_word(&mut self, address: usize) -> u16 {
+        if address == KEYBOARD_ADDRESS {
+            let high = self.status;
+            let low = self.on_read(address);
+            u16::from(low) | (u16::from(high) << 8)
+        } else {
+            let low = self.on_read(address);
+            let high = self.on_read(address + 1);
+            u16::from(low) | (u16::from(high) << 8)
+        }
+    }
+
+    fn poll(&mut self) {
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_ok() {
+            if let Some(character) = input.chars().next() {
+                self.keyboard = character as u8;
+                self.status = 1;
+            }
+        }
+    }
+}
+
 pub struct VM {
     registers: [i32; 13],
-    memory: Vec<u8>
+    flags: u8,
+    memory: Memory,
+    device: Box<dyn Device>,
+    state: State,
+    breakpoints: HashSet<usize>
 }
 
 impl VM {
-    pub fn new(code: Vec<u8>) -> VM {
-        // Expand available memory
-        const MAX_MEMORY: usize = 10_000_000; // 10MB
-        let mut memory = vec![0; MAX_MEMORY];
-
-        // Copy bytecode into memory
-        let mut i = code.len();
-        while i > 0 {
-            i -= 1;
-            memory[i] = code[i];
-        }
+    // `auto_map_pages` controls what happens when a program touches a page
+    // that hasn't been faulted in yet: `true` maps it on demand, `false`
+    // raises `RuntimeError::UnmappedPage`
+    pub fn new(code: Vec<u8>, device: Box<dyn Device>, auto_map_pages: bool) -> VM {
+        let mut memory = Memory::new(auto_map_pages);
+        memory.load(0, &code);
+
+        let mut registers = [0; 13];
+
+        // The stack grows downward from the top of the address space, so
+        // the stack pointer starts out pointing just past the last valid
+        // address
+        registers[Register::SP.to_bytecode() as usize] = ADDRESS_SPACE as i32;
+
         VM {
-            registers: [0; 13],
-            memory: memory
+            registers: registers,
+            flags: 0,
+            memory: memory,
+            device: device,
+            state: State::Init,
+            breakpoints: HashSet::new()
+        }
+    }
+
+    // Lets a host poll the attached device on its own schedule (e.g. once
+    // per frame) rather than on every instruction
+    pub fn poll_device(&mut self) {
+        self.device.poll();
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) {
+        self.breakpoints.remove(&address);
+    }
+
+    // Lets a direct `step`-based caller check the current PC against
+    // breakpoints itself, since `step` never reports them (see `StepResult`)
+    pub fn is_breakpoint(&self, address: usize) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    fn in_mmio_region(address: usize) -> bool {
+        address >= VRAM_ADDRESS && address < MMIO_END
+    }
+
+    // Maps a register index back to its conventional name for display
+    fn register_name(index: usize) -> String {
+        if index == Register::PC.to_bytecode() as usize {
+            "pc".to_string()
+        } else if index == Register::SP.to_bytecode() as usize {
+            "sp".to_string()
+        } else if index == Register::IO.to_bytecode() as usize {
+            "io".to_string()
+        } else {
+            format!("r{}", index)
+        }
+    }
+
+    // Prints all 13 named registers, the flags register, and a hex window
+    // of memory around `address`, for inspecting VM state while debugging
+    pub fn dump(&self, address: usize) {
+        println!("-- registers --");
+        for i in 0..self.registers.len() {
+            println!("{}: {}", VM::register_name(i), self.registers[i]);
+        }
+        println!("flags: {:#06b}", self.flags);
+
+        println!("-- memory @ {:#x} --", address);
+        let start = address.saturating_sub(16);
+        let end = (address + 16).min(ADDRESS_SPACE);
+        for offset in 0..(end - start) {
+            if offset % 16 == 0 {
+                print!("\n{:#010x}: ", start + offset);
+            }
+            print!("{:02x} ", self.memory.peek_byte(start + offset));
         }
+        println!();
     }
 
-    pub fn run(&mut self, start_address: usize) {
+    // Runs until the program halts or PC lands on a breakpoint. Breakpoints
+    // are only consulted here, before each instruction, so they never stop
+    // a direct call to `step` from advancing PC — call `step` once to move
+    // past a breakpoint, then `run` again with the address to resume from,
+    // since `run` always sets PC to the `start_address` it is given.
+    pub fn run(&mut self, start_address: usize) -> Result<HaltStatus, RuntimeError> {
         let pc = Register::PC.to_bytecode() as usize;
         self.registers[pc] = start_address as i32;
 
         loop {
-            let address = self.registers[pc] as usize;
-            let bytecode = {
-                let mut memory = Cursor::new(&mut self.memory[address..]);
-                [
-                    memory.read_i32::<LittleEndian>().unwrap(),
-                    memory.read_i32::<LittleEndian>().unwrap(),
-                    memory.read_i32::<LittleEndian>().unwrap(),
-                ]
-            };
-
-            let command = Command::from_bytecode(&bytecode);
-            let running = match command.cmd_type {
-                CommandType::Instruction(instruction) =>
-                    self.execute(instruction, &bytecode),
-                _ => false
-            };
-            if !running {
-                break;
+            let pc_address = self.registers[pc] as usize;
+            if self.breakpoints.contains(&pc_address) {
+                return Ok(HaltStatus::Breakpoint);
             }
 
-            self.registers[pc] += 12;
+            match self.step()? {
+                StepResult::Running => {},
+                StepResult::Halted(status) => return Ok(status)
+            }
+        }
+    }
+
+    // Executes exactly one instruction unconditionally, regardless of
+    // breakpoints; breakpoints only gate `run`'s loop, never single-stepping
+    pub fn step(&mut self) -> Result<StepResult, RuntimeError> {
+        let pc = Register::PC.to_bytecode() as usize;
+        let pc_address = self.registers[pc] as usize;
+
+        self.state = State::Running;
+
+        let address = self.check_address(pc_address, 12)?;
+        let bytecode = [
+            self.read_memory_i32(address)?,
+            self.read_memory_i32(address + 4)?,
+            self.read_memory_i32(address + 8)?,
+        ];
+
+        let command = Command::from_bytecode(&bytecode);
+        let running = match command.cmd_type {
+            CommandType::Instruction(instruction) =>
+                self.execute(instruction, &bytecode)?,
+            _ => return Err(RuntimeError::InvalidOpcode)
+        };
+
+        if !running {
+            self.state = State::Halted;
+            return Ok(StepResult::Halted(HaltStatus::End));
+        }
+
+        self.registers[pc] += 12;
+        Ok(StepResult::Running)
+    }
+
+    // Checks that `address` and the following `len - 1` bytes fall within
+    // the address space, returning the address itself so this can be
+    // chained with `?`. This doesn't guarantee the underlying pages are
+    // mapped; that's handled separately by `Memory`.
+    fn check_address(&self, address: usize, len: usize) -> Result<usize, RuntimeError> {
+        if len <= ADDRESS_SPACE && address <= ADDRESS_SPACE - len {
+            Ok(address)
+        } else {
+            Err(RuntimeError::InvalidMemoryAccess { address: address })
+        }
+    }
+
+    // Checks that `address` is aligned to `align` bytes, returning the
+    // address itself so this can be chained with `?`
+    fn check_alignment(&self, address: usize, align: usize) -> Result<usize, RuntimeError> {
+        if address % align == 0 {
+            Ok(address)
+        } else {
+            Err(RuntimeError::MemoryAlignment { address: address })
+        }
+    }
+
+    fn read_memory_i32(&mut self, address: usize) -> Result<i32, RuntimeError> {
+        let mut bytes = [0u8; 4];
+        for i in 0..4 {
+            bytes[i] = self.memory.read_byte(address + i)?;
+        }
+        Ok(Cursor::new(&bytes[..]).read_i32::<LittleEndian>().unwrap())
+    }
+
+    fn write_memory_i32(&mut self, address: usize, value: i32) -> Result<(), RuntimeError> {
+        let mut bytes = [0u8; 4];
+        let _ = (&mut bytes[..]).write_i32::<LittleEndian>(value);
+        for i in 0..4 {
+            self.memory.write_byte(address + i, bytes[i])?;
+        }
+        Ok(())
+    }
+
+    fn read_memory_u16(&mut self, address: usize) -> Result<u16, RuntimeError> {
+        let low = self.memory.read_byte(address)?;
+        let high = self.memory.read_byte(address + 1)?;
+        Ok(u16::from(low) | (u16::from(high) << 8))
+    }
+
+    fn write_memory_u16(&mut self, address: usize, value: u16) -> Result<(), RuntimeError> {
+        self.memory.write_byte(address, value as u8)?;
+        self.memory.write_byte(address + 1, (value >> 8) as u8)?;
+        Ok(())
+    }
+
+    // Checks that `register` names one of the 13 registers, returning the
+    // index itself so this can be chained with `?`
+    fn check_register(&self, register: usize) -> Result<usize, RuntimeError> {
+        if register < self.registers.len() {
+            Ok(register)
+        } else {
+            Err(RuntimeError::InvalidRegister)
+        }
+    }
+
+    // Updates the flags register from the result of an arithmetic op, along
+    // with the carry and overflow conditions computed by the caller
+    fn update_flags(&mut self, result: i32, carry: bool, overflow: bool) {
+        let mut flags = 0;
+        if result == 0 {
+            flags |= FLAG_ZERO;
+        }
+        if result < 0 {
+            flags |= FLAG_NEGATIVE;
         }
+        if carry {
+            flags |= FLAG_CARRY;
+        }
+        if overflow {
+            flags |= FLAG_OVERFLOW;
+        }
+        self.flags = flags;
     }
 
-    fn execute(&mut self, instruction: InstructionType, bytecode: &[i32; 3]) -> bool {
+    fn execute(&mut self, instruction: InstructionType, bytecode: &[i32; 3]) -> Result<bool, RuntimeError> {
         use tokenizer::InstructionType::*;
         match instruction {
             // Add together two registers and store the result in the first
             Add => {
-                let destination = bytecode[1] as usize;
-                let source = bytecode[2] as usize;
-                self.registers[destination] += self.registers[source];
+                let destination = self.check_register(bytecode[1] as usize)?;
+                let source = self.check_register(bytecode[2] as usize)?;
+                let a = self.registers[destination];
+                let b = self.registers[source];
+                let (result, carry, overflow) = match MathOpSubType::from_bytecode(bytecode[0]) {
+                    MathOpSubType::Signed => {
+                        let (result, overflow) = a.overflowing_add(b);
+                        let carry = a as u32 as i64 + b as u32 as i64 > u32::max_value() as i64;
+                        (result, carry, overflow)
+                    },
+                    MathOpSubType::Unsigned => {
+                        let (result, overflow) = (a as u32).overflowing_add(b as u32);
+                        (result as i32, overflow, overflow)
+                    },
+                    MathOpSubType::FloatingPoint => {
+                        let result = f32::from_bits(a as u32) + f32::from_bits(b as u32);
+                        (result.to_bits() as i32, false, false)
+                    }
+                };
+                self.registers[destination] = result;
+                self.update_flags(result, carry, overflow);
             },
 
             // Add an immediate value to a register
             AddImmediate => {
-                let register = bytecode[1] as usize;
-                let value = bytecode[2];
-                self.registers[register] += value;
+                let register = self.check_register(bytecode[1] as usize)?;
+                let a = self.registers[register];
+                let b = bytecode[2];
+                let (result, overflow) = a.overflowing_add(b);
+                let carry = a as u32 as i64 + b as u32 as i64 > u32::max_value() as i64;
+                self.registers[register] = result;
+                self.update_flags(result, carry, overflow);
+            },
+
+            // Call a subroutine at the given address, pushing the return
+            // address onto the stack pointed to by the SP register
+            Call => {
+                let address = bytecode[1];
+                // Remove offset that will be automatically applied
+                let address = address - 12;
+
+                let sp = Register::SP.to_bytecode() as usize;
+                let pc = Register::PC.to_bytecode() as usize;
+                let return_address = self.registers[pc] + 12;
+
+                self.registers[sp] -= 4;
+                let stack_address = self.check_address(self.registers[sp] as usize, 4)?;
+                self.write_memory_i32(stack_address, return_address)?;
+
+                self.registers[pc] = address;
+            },
+
+            // Return from a subroutine, popping the return address off of
+            // the stack pointed to by the SP register
+            Return => {
+                let sp = Register::SP.to_bytecode() as usize;
+                let pc = Register::PC.to_bytecode() as usize;
+
+                let stack_address = self.check_address(self.registers[sp] as usize, 4)?;
+                let return_address = self.read_memory_i32(stack_address)?;
+                self.registers[sp] += 4;
+
+                // Remove offset that will be automatically applied
+                self.registers[pc] = return_address - 12;
             },
 
             // Perform a boolean AND on two registers
             And => {
-                let reg1 = bytecode[1] as usize;
-                let reg2 = bytecode[2] as usize;
+                let reg1 = self.check_register(bytecode[1] as usize)?;
+                let reg2 = self.check_register(bytecode[2] as usize)?;
                 let reg1_value = self.registers[reg1];
                 let reg2_value = self.registers[reg2];
                 self.registers[reg1] = if reg1_value != 0 && reg2_value != 0 {
@@ -84,29 +562,39 @@ impl VM {
                 } else {
                     0
                 };
+                let result = self.registers[reg1];
+                self.update_flags(result, false, false);
             },
 
-            // Compares the contents of two registers
-            // -1 if the first is less than the second
-            // 1  if the first is greater than the second
-            // 0  if they're equal
+            // Compares the contents of two registers by subtracting the
+            // second from the first and setting the flags register from the
+            // result, leaving both registers untouched
             Compare => {
-                let reg1 = bytecode[1] as usize;
-                let reg2 = bytecode[2] as usize;
+                let reg1 = self.check_register(bytecode[1] as usize)?;
+                let reg2 = self.check_register(bytecode[2] as usize)?;
                 let val1 = self.registers[reg1];
                 let val2 = self.registers[reg2];
-                self.registers[reg1] = if val1 < val2 {
-                    -1
-                } else if val1 > val2 {
-                    1
-                } else {
-                    0
+                let (result, carry, overflow) = match MathOpSubType::from_bytecode(bytecode[0]) {
+                    MathOpSubType::Signed => {
+                        let (result, overflow) = val1.overflowing_sub(val2);
+                        let carry = (val1 as u32 as i64) < (val2 as u32 as i64);
+                        (result, carry, overflow)
+                    },
+                    MathOpSubType::Unsigned => {
+                        let (result, overflow) = (val1 as u32).overflowing_sub(val2 as u32);
+                        (result as i32, overflow, overflow)
+                    },
+                    MathOpSubType::FloatingPoint => {
+                        let result = f32::from_bits(val1 as u32) - f32::from_bits(val2 as u32);
+                        (result.to_bits() as i32, false, false)
+                    }
                 };
+                self.update_flags(result, carry, overflow);
             },
 
             // Jumps to an address if the given register contains a zero value
             CompareZeroJump => {
-                let register = bytecode[1] as usize;
+                let register = self.check_register(bytecode[1] as usize)?;
                 let address = bytecode[2];
                 // Remove offset that will be automatically applied
                 let address = address - 12;
@@ -139,17 +627,46 @@ impl VM {
                 };
             },
 
-            // Perform integer division between two registers
+            // Perform division between two registers
             Divide => {
-                let destination = bytecode[1] as usize;
-                let source = bytecode[2] as usize;
-                self.registers[destination] /= self.registers[source];
+                let destination = self.check_register(bytecode[1] as usize)?;
+                let source = self.check_register(bytecode[2] as usize)?;
+                match MathOpSubType::from_bytecode(bytecode[0]) {
+                    MathOpSubType::Signed => {
+                        let divisor = self.registers[source];
+                        if divisor == 0 {
+                            return Err(RuntimeError::DivideByZero);
+                        }
+                        // i32::MIN / -1 overflows and panics even with the
+                        // checked-divisor guard above, so go through
+                        // checked_div rather than `/=`
+                        self.registers[destination] = self.registers[destination]
+                            .checked_div(divisor)
+                            .ok_or(RuntimeError::ArithmeticOverflow)?;
+                    },
+                    MathOpSubType::Unsigned => {
+                        let divisor = self.registers[source] as u32;
+                        if divisor == 0 {
+                            return Err(RuntimeError::DivideByZero);
+                        }
+                        self.registers[destination] = (self.registers[destination] as u32 / divisor) as i32;
+                    },
+                    MathOpSubType::FloatingPoint => {
+                        // Float division by zero yields an IEEE infinity/NaN
+                        // rather than trapping
+                        let a = f32::from_bits(self.registers[destination] as u32);
+                        let b = f32::from_bits(self.registers[source] as u32);
+                        self.registers[destination] = (a / b).to_bits() as i32;
+                    }
+                }
+                let result = self.registers[destination];
+                self.update_flags(result, false, false);
             },
 
             // If the contents of a register are greater than 0
             // jump to the specified address
             GreaterThanZeroJump => {
-                let register = bytecode[1] as usize;
+                let register = self.check_register(bytecode[1] as usize)?;
                 let address = bytecode[2];
                 // Remove offset that will be automatically applied
                 let address = address - 12;
@@ -194,9 +711,49 @@ impl VM {
                 self.registers[Register::PC as usize] = address;
             },
 
+            // Jumps to an address if the Zero flag is set
+            JumpEqual => {
+                let address = bytecode[1];
+                // Remove offset that will be automatically applied
+                let address = address - 12;
+                if self.flags & FLAG_ZERO != 0 {
+                    self.registers[Register::PC as usize] = address;
+                }
+            },
+
+            // Jumps to an address if the Zero flag is clear
+            JumpNotEqual => {
+                let address = bytecode[1];
+                // Remove offset that will be automatically applied
+                let address = address - 12;
+                if self.flags & FLAG_ZERO == 0 {
+                    self.registers[Register::PC as usize] = address;
+                }
+            },
+
+            // Jumps to an address if the Carry flag is set
+            JumpCarry => {
+                let address = bytecode[1];
+                // Remove offset that will be automatically applied
+                let address = address - 12;
+                if self.flags & FLAG_CARRY != 0 {
+                    self.registers[Register::PC as usize] = address;
+                }
+            },
+
+            // Jumps to an address if the Overflow flag is set
+            JumpOverflow => {
+                let address = bytecode[1];
+                // Remove offset that will be automatically applied
+                let address = address - 12;
+                if self.flags & FLAG_OVERFLOW != 0 {
+                    self.registers[Register::PC as usize] = address;
+                }
+            },
+
             // Jumps to an address stored in a register
             JumpRelative => {
-                let register = bytecode[1] as usize;
+                let register = self.check_register(bytecode[1] as usize)?;
                 let address = self.registers[register];
 
                 // Remove offset that will be automatically applied
@@ -207,7 +764,7 @@ impl VM {
             // If the contents of a register are less than 0
             // jump to the specified address
             LessThanZeroJump => {
-                let register = bytecode[1] as usize;
+                let register = self.check_register(bytecode[1] as usize)?;
                 let address = bytecode[2];
                 // Remove offset that will be automatically applied
                 let address = address - 12;
@@ -218,49 +775,72 @@ impl VM {
 
             // Loads the address of a label into a register
             LoadAddress => {
-                let register = bytecode[1] as usize;
+                let register = self.check_register(bytecode[1] as usize)?;
                 let address = bytecode[2];
                 self.registers[register] = address;
             },
 
             // Load a byte of data from memory and place it into a register
             LoadByte => {
-                let register = bytecode[1] as usize;
-                let address = bytecode[2] as usize;
-                let mut memory = Cursor::new(&mut self.memory[address..]);
-                let value = memory.read_u8().unwrap();
+                let register = self.check_register(bytecode[1] as usize)?;
+                let address = self.check_address(bytecode[2] as usize, 1)?;
+                let value = if VM::in_mmio_region(address) {
+                    self.device.on_read(address)
+                } else {
+                    self.memory.read_byte(address)?
+                };
                 self.registers[register] = value as i32;
             },
 
             // Load a word of data from memory and place it into a register
             LoadWord => {
-                let register = bytecode[1] as usize;
-                let address = bytecode[2] as usize;
-                let mut memory = Cursor::new(&mut self.memory[address..]);
-                let value = memory.read_u16::<LittleEndian>().unwrap();
+                let register = self.check_register(bytecode[1] as usize)?;
+                let address = self.check_address(bytecode[2] as usize, 2)?;
+                let address = self.check_alignment(address, 2)?;
+                let value = if VM::in_mmio_region(address) {
+                    self.device.on_read_word(address)
+                } else {
+                    self.read_memory_u16(address)?
+                };
                 self.registers[register] = value as i32;
             },
 
             // Copy a value from register B and place it in register A
             Move => {
-                let reg_a = bytecode[1] as usize;
-                let reg_b = bytecode[2] as usize;
+                let reg_a = self.check_register(bytecode[1] as usize)?;
+                let reg_b = self.check_register(bytecode[2] as usize)?;
                 let val_b = self.registers[reg_b];
                 self.registers[reg_a] = val_b;
             },
 
             // Multiply the values in two registers together and store it in the first
             Multiply => {
-                let reg_a = bytecode[1] as usize;
-                let reg_b = bytecode[2] as usize;
+                let reg_a = self.check_register(bytecode[1] as usize)?;
+                let reg_b = self.check_register(bytecode[2] as usize)?;
                 let val_a = self.registers[reg_a];
                 let val_b = self.registers[reg_b];
-                self.registers[reg_a] = val_a * val_b;
+                let (result, carry, overflow) = match MathOpSubType::from_bytecode(bytecode[0]) {
+                    MathOpSubType::Signed => {
+                        let (result, overflow) = val_a.overflowing_mul(val_b);
+                        let carry = val_a as u32 as u64 * val_b as u32 as u64 > u32::max_value() as u64;
+                        (result, carry, overflow)
+                    },
+                    MathOpSubType::Unsigned => {
+                        let (result, overflow) = (val_a as u32).overflowing_mul(val_b as u32);
+                        (result as i32, overflow, overflow)
+                    },
+                    MathOpSubType::FloatingPoint => {
+                        let result = f32::from_bits(val_a as u32) * f32::from_bits(val_b as u32);
+                        (result.to_bits() as i32, false, false)
+                    }
+                };
+                self.registers[reg_a] = result;
+                self.update_flags(result, carry, overflow);
             },
 
             // Jumps to an address if the given register contains a non-zero value
             NonZeroJump => {
-                let register = bytecode[1] as usize;
+                let register = self.check_register(bytecode[1] as usize)?;
                 let address = bytecode[2];
                 // Remove offset that will be automatically applied
                 let address = address - 12;
@@ -272,8 +852,8 @@ impl VM {
             // If one of the registers contains a non-zero value, store 1
             // Otherwise, store 0 in the first register
             Or => {
-                let reg1 = bytecode[1] as usize;
-                let reg2 = bytecode[2] as usize;
+                let reg1 = self.check_register(bytecode[1] as usize)?;
+                let reg2 = self.check_register(bytecode[2] as usize)?;
                 let reg1_value = self.registers[reg1];
                 let reg2_value = self.registers[reg2];
                 self.registers[reg1] = if reg1_value != 0 || reg2_value != 0 {
@@ -281,6 +861,8 @@ impl VM {
                 } else {
                     0
                 };
+                let result = self.registers[reg1];
+                self.update_flags(result, false, false);
             },
 
             // Print out an ASCII character to stdout
@@ -295,35 +877,354 @@ impl VM {
 
             // Stores a byte of data at a location
             StoreByte => {
-                let register = bytecode[1] as usize;
-                let address = bytecode[2] as usize;
+                let register = self.check_register(bytecode[1] as usize)?;
+                let address = self.check_address(bytecode[2] as usize, 1)?;
                 let value = self.registers[register] as u8;
-                let mut memory = &mut self.memory[address..];
-                let _ = memory.write_u8(value);
+                if VM::in_mmio_region(address) {
+                    self.device.on_write(address, value);
+                } else {
+                    self.memory.write_byte(address, value)?;
+                }
             },
 
             // Stores a word of data at a location
             StoreWord => {
-                let register = bytecode[1] as usize;
-                let address = bytecode[2] as usize;
+                let register = self.check_register(bytecode[1] as usize)?;
+                let address = self.check_address(bytecode[2] as usize, 2)?;
+                let address = self.check_alignment(address, 2)?;
                 let value = self.registers[register] as u16;
-                let mut memory = &mut self.memory[address..];
-                let _ = memory.write_u16::<LittleEndian>(value);
+                if VM::in_mmio_region(address) {
+                    self.device.on_write(address, value as u8);
+                    self.device.on_write(address + 1, (value >> 8) as u8);
+                } else {
+                    self.write_memory_u16(address, value)?;
+                }
             },
 
             // Subtracts the value in register A from register B
             // and stores it in register A
             Subtract => {
-                let reg_a = bytecode[1] as usize;
-                let reg_b = bytecode[2] as usize;
+                let reg_a = self.check_register(bytecode[1] as usize)?;
+                let reg_b = self.check_register(bytecode[2] as usize)?;
                 let val_a = self.registers[reg_a];
                 let val_b = self.registers[reg_b];
-                self.registers[reg_a] = val_a - val_b;
+                let (result, carry, overflow) = match MathOpSubType::from_bytecode(bytecode[0]) {
+                    MathOpSubType::Signed => {
+                        let (result, overflow) = val_a.overflowing_sub(val_b);
+                        let carry = (val_a as u32 as i64) < (val_b as u32 as i64);
+                        (result, carry, overflow)
+                    },
+                    MathOpSubType::Unsigned => {
+                        let (result, overflow) = (val_a as u32).overflowing_sub(val_b as u32);
+                        (result as i32, overflow, overflow)
+                    },
+                    MathOpSubType::FloatingPoint => {
+                        let result = f32::from_bits(val_a as u32) - f32::from_bits(val_b as u32);
+                        (result.to_bits() as i32, false, false)
+                    }
+                };
+                self.registers[reg_a] = result;
+                self.update_flags(result, carry, overflow);
             },
 
             // End the program
-            End => return false
+            End => return Ok(false)
         };
-        true
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct MockDevice;
+
+    impl Device for MockDevice {
+        fn on_write(&mut self, _address: usize, _value: u8) {}
+        fn on_read(&mut self, _address: usize) -> u8 { 0 }
+        fn poll(&mut self) {}
+    }
+
+    fn new_vm() -> VM {
+        VM::new(vec![], Box::new(MockDevice), true)
+    }
+
+    // Records every write it sees and answers every read with a sentinel
+    // that Memory would never produce, so a test can tell whether an MMIO
+    // access actually reached the device instead of falling through to
+    // memory
+    struct RecordingDevice {
+        writes: Rc<RefCell<Vec<(usize, u8)>>>
+    }
+
+    impl Device for RecordingDevice {
+        fn on_write(&mut self, address: usize, value: u8) {
+            self.writes.borrow_mut().push((address, value));
+        }
+
+        fn on_read(&mut self, _address: usize) -> u8 {
+            0xAB
+        }
+
+        fn poll(&mut self) {}
+    }
+
+    #[test]
+    fn new_initializes_stack_pointer_to_top_of_address_space() {
+        let vm = new_vm();
+        let sp = Register::SP.to_bytecode() as usize;
+        assert_eq!(vm.registers[sp], ADDRESS_SPACE as i32);
+    }
+
+    #[test]
+    fn check_address_rejects_addresses_outside_the_address_space() {
+        let vm = new_vm();
+        assert_eq!(vm.check_address(ADDRESS_SPACE - 4, 4), Ok(ADDRESS_SPACE - 4));
+        assert_eq!(
+            vm.check_address(ADDRESS_SPACE - 3, 4),
+            Err(RuntimeError::InvalidMemoryAccess { address: ADDRESS_SPACE - 3 })
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn check_register_rejects_indices_past_the_13_registers() {
+        let vm = new_vm();
+        assert_eq!(vm.check_register(12), Ok(12));
+        assert_eq!(vm.check_register(13), Err(RuntimeError::InvalidRegister));
+    }
+
+    #[test]
+    fn update_flags_sets_zero_for_a_zero_result() {
+        let mut vm = new_vm();
+        vm.update_flags(0, false, false);
+        assert_eq!(vm.flags, FLAG_ZERO);
+    }
+
+    #[test]
+    fn update_flags_sets_negative_carry_and_overflow() {
+        let mut vm = new_vm();
+        vm.update_flags(-1, true, true);
+        assert_eq!(vm.flags, FLAG_NEGATIVE | FLAG_CARRY | FLAG_OVERFLOW);
+    }
+
+    #[test]
+    fn math_op_sub_type_decodes_from_the_opcode_word() {
+        assert_eq!(MathOpSubType::from_bytecode(0), MathOpSubType::Signed);
+        assert_eq!(MathOpSubType::from_bytecode(1 << 16), MathOpSubType::Unsigned);
+        assert_eq!(MathOpSubType::from_bytecode(2 << 16), MathOpSubType::FloatingPoint);
+    }
+
+    #[test]
+    fn in_mmio_region_covers_vram_through_device_status() {
+        assert!(!VM::in_mmio_region(VRAM_ADDRESS - 1));
+        assert!(VM::in_mmio_region(VRAM_ADDRESS));
+        assert!(VM::in_mmio_region(DEVICE_STATUS_ADDRESS));
+        assert!(!VM::in_mmio_region(DEVICE_STATUS_ADDRESS + 1));
+    }
+
+    #[test]
+    fn breakpoints_can_be_added_and_removed() {
+        let mut vm = new_vm();
+        vm.add_breakpoint(42);
+        assert!(vm.breakpoints.contains(&42));
+
+        vm.remove_breakpoint(42);
+        assert!(!vm.breakpoints.contains(&42));
+    }
+
+    #[test]
+    fn register_name_names_pc_sp_and_io_and_falls_back_to_rn() {
+        let pc = Register::PC.to_bytecode() as usize;
+        let sp = Register::SP.to_bytecode() as usize;
+        let io = Register::IO.to_bytecode() as usize;
+
+        assert_eq!(VM::register_name(pc), "pc");
+        assert_eq!(VM::register_name(sp), "sp");
+        assert_eq!(VM::register_name(io), "io");
+        assert_eq!(VM::register_name(0), "r0");
+    }
+
+    #[test]
+    fn memory_read_write_roundtrips_when_auto_mapped() {
+        let mut memory = Memory::new(true);
+        assert_eq!(memory.write_byte(100, 42), Ok(()));
+        assert_eq!(memory.read_byte(100), Ok(42));
+    }
+
+    #[test]
+    fn memory_read_faults_on_an_unmapped_page_without_auto_map() {
+        let mut memory = Memory::new(false);
+        assert_eq!(
+            memory.read_byte(100),
+            Err(RuntimeError::UnmappedPage { address: 100 })
+        );
+    }
+
+    #[test]
+    fn check_alignment_rejects_unaligned_addresses() {
+        let vm = new_vm();
+        assert_eq!(vm.check_alignment(8, 4), Ok(8));
+        assert_eq!(
+            vm.check_alignment(7, 4),
+            Err(RuntimeError::MemoryAlignment { address: 7 })
+        );
+    }
+
+    #[test]
+    fn call_and_return_round_trip_through_the_stack_and_resume_at_the_caller() {
+        let mut vm = new_vm();
+        let pc = Register::PC.to_bytecode() as usize;
+        let sp = Register::SP.to_bytecode() as usize;
+
+        vm.registers[pc] = 100;
+        let initial_sp = vm.registers[sp];
+
+        // Jump targets and return addresses carry the automatic +12 PC
+        // advance baked in, same as the jump instructions in `execute` strip off
+        vm.execute(InstructionType::Call, &[0, 500 + 12, 0]).unwrap();
+        assert_eq!(vm.registers[pc], 500);
+        assert_eq!(vm.registers[sp], initial_sp - 4);
+
+        vm.execute(InstructionType::Return, &[0, 0, 0]).unwrap();
+        assert_eq!(vm.registers[pc], 100);
+        assert_eq!(vm.registers[sp], initial_sp);
+    }
+
+    #[test]
+    fn run_reports_an_out_of_bounds_program_counter_as_an_error_instead_of_panicking() {
+        let mut vm = new_vm();
+        assert_eq!(
+            vm.run(ADDRESS_SPACE),
+            Err(RuntimeError::InvalidMemoryAccess { address: ADDRESS_SPACE })
+        );
+    }
+
+    #[test]
+    fn signed_add_sets_negative_and_overflow_flags_on_real_overflow() {
+        let mut vm = new_vm();
+        vm.registers[0] = i32::MAX;
+        vm.registers[1] = 1;
+
+        vm.execute(InstructionType::Add, &[0, 0, 1]).unwrap();
+
+        assert_eq!(vm.registers[0], i32::MIN);
+        assert_eq!(vm.flags, FLAG_NEGATIVE | FLAG_OVERFLOW);
+    }
+
+    #[test]
+    fn signed_subtract_sets_carry_on_a_real_borrow() {
+        let mut vm = new_vm();
+        vm.registers[0] = 0;
+        vm.registers[1] = 1;
+
+        vm.execute(InstructionType::Subtract, &[0, 0, 1]).unwrap();
+
+        assert_eq!(vm.registers[0], -1);
+        assert_eq!(vm.flags, FLAG_NEGATIVE | FLAG_CARRY);
+    }
+
+    #[test]
+    fn signed_multiply_sets_overflow_on_a_real_overflow() {
+        let mut vm = new_vm();
+        vm.registers[0] = i32::MAX;
+        vm.registers[1] = 2;
+
+        vm.execute(InstructionType::Multiply, &[0, 0, 1]).unwrap();
+
+        assert_eq!(vm.flags & FLAG_OVERFLOW, FLAG_OVERFLOW);
+    }
+
+    #[test]
+    fn compare_sets_the_zero_flag_when_the_operands_are_equal() {
+        let mut vm = new_vm();
+        vm.registers[0] = 7;
+        vm.registers[1] = 7;
+
+        vm.execute(InstructionType::Compare, &[0, 0, 1]).unwrap();
+
+        assert_eq!(vm.flags, FLAG_ZERO);
+        assert_eq!(vm.registers[0], 7);
+        assert_eq!(vm.registers[1], 7);
+    }
+
+    #[test]
+    fn signed_divide_traps_i32_min_by_minus_one_instead_of_panicking() {
+        let mut vm = new_vm();
+        vm.registers[0] = i32::MIN;
+        vm.registers[1] = -1;
+
+        assert_eq!(
+            vm.execute(InstructionType::Divide, &[0, 0, 1]),
+            Err(RuntimeError::ArithmeticOverflow)
+        );
+    }
+
+    #[test]
+    fn store_byte_and_load_byte_in_the_mmio_region_reach_the_device_not_memory() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let device = RecordingDevice { writes: writes.clone() };
+        let mut vm = VM::new(vec![], Box::new(device), true);
+
+        vm.registers[0] = 'A' as i32;
+        vm.execute(InstructionType::StoreByte, &[0, 0, VRAM_ADDRESS as i32]).unwrap();
+        assert_eq!(*writes.borrow(), vec![(VRAM_ADDRESS, b'A')]);
+
+        vm.execute(InstructionType::LoadByte, &[0, 0, VRAM_ADDRESS as i32]).unwrap();
+        assert_eq!(vm.registers[0], 0xAB);
+    }
+
+    #[test]
+    fn store_word_in_the_mmio_region_reaches_the_device_as_two_byte_writes() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let device = RecordingDevice { writes: writes.clone() };
+        let mut vm = VM::new(vec![], Box::new(device), true);
+
+        vm.registers[0] = 0x1234;
+        vm.execute(InstructionType::StoreWord, &[0, 0, VRAM_ADDRESS as i32]).unwrap();
+
+        assert_eq!(*writes.borrow(), vec![(VRAM_ADDRESS, 0x34), (VRAM_ADDRESS + 1, 0x12)]);
+    }
+
+    #[test]
+    fn load_word_at_keyboard_address_observes_status_before_it_gets_cleared() {
+        let mut device = TerminalDevice::new();
+        device.keyboard = b'x';
+        device.status = 1;
+        let mut vm = VM::new(vec![], Box::new(device), true);
+
+        vm.execute(InstructionType::LoadWord, &[0, 0, KEYBOARD_ADDRESS as i32]).unwrap();
+
+        let low = vm.registers[0] as u16 & 0xff;
+        let high = (vm.registers[0] as u16 >> 8) & 0xff;
+        assert_eq!(low, b'x' as u16);
+        assert_eq!(high, 1, "status byte should reflect the pending keystroke, not the zeroed value on_read leaves behind");
+    }
+
+    #[test]
+    fn run_stops_immediately_when_starting_on_a_breakpoint() {
+        let mut vm = new_vm();
+        vm.add_breakpoint(200);
+        assert_eq!(vm.run(200), Ok(HaltStatus::Breakpoint));
+    }
+
+    #[test]
+    fn is_breakpoint_reflects_the_current_breakpoint_set() {
+        let mut vm = new_vm();
+        assert!(!vm.is_breakpoint(300));
+
+        vm.add_breakpoint(300);
+        assert!(vm.is_breakpoint(300));
+
+        vm.remove_breakpoint(300);
+        assert!(!vm.is_breakpoint(300));
+    }
+
+    #[test]
+    fn run_reports_an_unmapped_instruction_page_without_auto_mapping() {
+        let mut vm = VM::new(vec![], Box::new(MockDevice), false);
+        assert_eq!(vm.run(0), Err(RuntimeError::UnmappedPage { address: 0 }));
+    }
+}